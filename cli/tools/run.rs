@@ -1,6 +1,9 @@
 // Copyright 2018-2023 the Deno authors. All rights reserved. MIT license.
 
+use std::cell::Cell;
+use std::cell::RefCell;
 use std::io::Read;
+use std::rc::Rc;
 
 use deno_ast::MediaType;
 use deno_ast::ModuleSpecifier;
@@ -13,6 +16,7 @@ use crate::args::Flags;
 use crate::file_fetcher::File;
 use crate::proc_state::ProcState;
 use crate::util;
+use crate::worker;
 
 pub async fn run_script(flags: Flags) -> Result<i32, AnyError> {
   if !flags.has_permission() && flags.has_permission_in_argv() {
@@ -57,6 +61,7 @@ To grant permissions, set them before the script argument. For example:
 }
 
 pub async fn run_from_stdin(flags: Flags) -> Result<i32, AnyError> {
+  let ext = flags.ext.clone();
   let ps = ProcState::from_flags(flags).await?;
   let main_module = ps.options.resolve_main_module()?;
 
@@ -65,12 +70,14 @@ pub async fn run_from_stdin(flags: Flags) -> Result<i32, AnyError> {
   )?);
   let mut source = Vec::new();
   std::io::stdin().read_to_end(&mut source)?;
+  let source = String::from_utf8(source)?;
+  let media_type = resolve_media_type(&ext, &source);
   // Create a dummy source file.
   let source_file = File {
     local: main_module.clone().to_file_path().unwrap(),
     maybe_types: None,
-    media_type: MediaType::TypeScript,
-    source: String::from_utf8(source)?.into(),
+    media_type,
+    source: source.into(),
     specifier: main_module.clone(),
     maybe_headers: None,
   };
@@ -86,27 +93,74 @@ pub async fn run_from_stdin(flags: Flags) -> Result<i32, AnyError> {
   Ok(exit_code)
 }
 
-// TODO(bartlomieju): this function is not handling `exit_code` set by the runtime
-// code properly.
 async fn run_with_watch(flags: Flags) -> Result<i32, AnyError> {
+  let hmr = flags.watch.as_ref().map(|w| w.hmr).unwrap_or(false);
   let (sender, receiver) = tokio::sync::mpsc::unbounded_channel();
   let mut ps =
     ProcState::from_flags_for_file_watcher(flags, sender.clone()).await?;
   let clear_screen = !ps.options.no_clear_screen();
   let main_module = ps.options.resolve_main_module()?;
 
+  // In HMR mode the worker created for the first run is kept alive across
+  // subsequent file-watcher iterations instead of being torn down, so that
+  // top-level state (timers, open sockets, server handles, ...) survives a
+  // module update.
+  let hmr_worker: Rc<RefCell<Option<worker::CliMainWorker>>> =
+    Rc::new(RefCell::new(None));
+
+  // Tracks the entry module's own source as of the last HMR iteration, so a
+  // later iteration can tell whether it actually changed.
+  let hmr_previous_source: Rc<RefCell<Option<String>>> =
+    Rc::new(RefCell::new(None));
+
+  // Remembers the exit code of the most recently completed run, so that when
+  // the watcher itself is terminated we can report the last script's actual
+  // exit status instead of always exiting with 0.
+  let last_exit_code = Rc::new(Cell::new(0));
+
   let operation = |main_module: ModuleSpecifier| {
     ps.reset_for_file_watcher();
     let ps = ps.clone();
+    let hmr_worker = hmr_worker.clone();
+    let hmr_previous_source = hmr_previous_source.clone();
+    let last_exit_code = last_exit_code.clone();
     Ok(async move {
       let permissions = PermissionsContainer::new(Permissions::from_options(
         &ps.options.permissions_options(),
       )?);
-      let worker_factory = ps.into_cli_main_worker_factory();
-      let worker = worker_factory
-        .create_main_worker(main_module, permissions)
-        .await?;
-      worker.run_for_watcher().await?;
+
+      if hmr {
+        if let Some(worker) = hmr_worker.borrow_mut().as_mut() {
+          // Incremental update: if the entry module's own source actually
+          // changed, replay it into the already-running worker, which
+          // resumes its own event loop afterwards instead of re-executing
+          // `main_module` from scratch.
+          let changed_modules =
+            changed_main_module(&main_module, &hmr_previous_source)?;
+          if !changed_modules.is_empty() {
+            worker.apply_module_update(changed_modules).await?;
+          }
+          return Ok(());
+        }
+
+        let worker_factory = ps.into_cli_main_worker_factory();
+        let mut worker = worker_factory
+          .create_main_worker(main_module.clone(), permissions)
+          .await?;
+        // Seed the cache with what we're about to execute, so the first
+        // real edit is diffed against this run instead of an empty cache.
+        changed_main_module(&main_module, &hmr_previous_source)?;
+        let exit_code = worker.run_for_watcher().await?;
+        last_exit_code.set(exit_code);
+        *hmr_worker.borrow_mut() = Some(worker);
+      } else {
+        let worker_factory = ps.into_cli_main_worker_factory();
+        let mut worker = worker_factory
+          .create_main_worker(main_module, permissions)
+          .await?;
+        let exit_code = worker.run_for_watcher().await?;
+        last_exit_code.set(exit_code);
+      }
 
       Ok(())
     })
@@ -123,13 +177,37 @@ async fn run_with_watch(flags: Flags) -> Result<i32, AnyError> {
   )
   .await?;
 
-  Ok(0)
+  Ok(last_exit_code.get())
+}
+
+/// Re-reads `main_module`'s own source from disk and reports whether it
+/// changed since the last call, seeding `previous_source` as it goes.
+/// Scoped to the entry module only for now - diffing its full transitive
+/// dependency graph is tracked as a follow-up.
+fn changed_main_module(
+  main_module: &ModuleSpecifier,
+  previous_source: &Rc<RefCell<Option<String>>>,
+) -> Result<Vec<ModuleSpecifier>, AnyError> {
+  let Ok(path) = main_module.to_file_path() else {
+    return Ok(Vec::new());
+  };
+  let source = std::fs::read_to_string(path)?;
+  let mut previous_source = previous_source.borrow_mut();
+  let is_changed = previous_source.as_ref() != Some(&source);
+  *previous_source = Some(source);
+
+  Ok(if is_changed {
+    vec![main_module.clone()]
+  } else {
+    Vec::new()
+  })
 }
 
 pub async fn eval_command(
   flags: Flags,
   eval_flags: EvalFlags,
 ) -> Result<i32, AnyError> {
+  let ext = flags.ext.clone();
   let ps = ProcState::from_flags(flags).await?;
   let main_module = ps.options.resolve_main_module()?;
   let permissions = PermissionsContainer::new(Permissions::from_options(
@@ -140,14 +218,14 @@ pub async fn eval_command(
     format!("console.log({})", eval_flags.code)
   } else {
     eval_flags.code
-  }
-  .into_bytes();
+  };
+  let media_type = resolve_media_type(&ext, &source_code);
 
   let file = File {
     local: main_module.clone().to_file_path().unwrap(),
     maybe_types: None,
-    media_type: MediaType::Unknown,
-    source: String::from_utf8(source_code)?.into(),
+    media_type,
+    source: source_code.into(),
     specifier: main_module.clone(),
     maybe_headers: None,
   };
@@ -163,3 +241,165 @@ pub async fn eval_command(
   let exit_code = worker.run().await?;
   Ok(exit_code)
 }
+
+/// Maps the `--ext` flag, when provided, onto the `MediaType` it names;
+/// otherwise falls back to sniffing `source` for a shebang and inline
+/// language hints. Shared by `run_from_stdin` and `eval_command`, which both
+/// construct a dummy `File` for source that didn't come from a file with a
+/// real extension.
+fn resolve_media_type(ext: &Option<String>, source: &str) -> MediaType {
+  match ext.as_deref() {
+    Some("ts") => MediaType::TypeScript,
+    Some("tsx") => MediaType::Tsx,
+    Some("js") => MediaType::JavaScript,
+    Some("jsx") => MediaType::Jsx,
+    Some("mjs") => MediaType::Mjs,
+    Some(other) => {
+      log::warn!(
+        "{}",
+        crate::colors::yellow(format!(
+          "Unrecognized --ext \"{other}\", inferring media type instead."
+        ))
+      );
+      detect_media_type(source)
+    }
+    None => detect_media_type(source),
+  }
+}
+
+/// Skips a leading shebang, then guesses a media type for `source`:
+/// `MediaType::JavaScript` when the first remaining line carries a
+/// `@ts-check`, `@ts-nocheck` or `@ts-expect-error` directive,
+/// `MediaType::Tsx` when one of the first 20 lines looks like a JSX tag, and
+/// `MediaType::Unknown` (parsed as plain JS) otherwise, so that JS piped into
+/// `deno run -` isn't needlessly type-stripped. It never returns
+/// `MediaType::TypeScript`.
+fn detect_media_type(source: &str) -> MediaType {
+  let mut lines = source.lines();
+  let mut first_line = lines.next().unwrap_or_default();
+  if first_line.starts_with("#!") {
+    first_line = lines.next().unwrap_or_default();
+  }
+
+  // `@ts-check`/`@ts-nocheck`/`@ts-expect-error` are JSDoc-era directives for
+  // type-checking plain JavaScript - a genuine `.ts` file never needs them -
+  // so finding one is evidence of JS, not TS.
+  if first_line.contains("@ts-check")
+    || first_line.contains("@ts-nocheck")
+    || first_line.contains("@ts-expect-error")
+  {
+    return MediaType::JavaScript;
+  }
+
+  if looks_like_jsx(source) {
+    return MediaType::Tsx;
+  }
+
+  MediaType::Unknown
+}
+
+/// A narrow, tag-shaped heuristic for JSX/TSX, limited to the first few
+/// lines: an opening `<Name` token paired with either a matching `</Name>`
+/// or a `/>` self-close later on the *same line*. Pairing on the tag name
+/// and keeping the search line-local (rather than just checking that *some*
+/// open-like and close-like token exist anywhere in the file) avoids
+/// misfiring on an ordinary script that merely mentions both kinds of token
+/// without containing an actual tag - e.g. an HTML-building string, or a
+/// regex literal like `/<div>/` on one line and an unrelated `</` or `/>` on
+/// another.
+fn looks_like_jsx(source: &str) -> bool {
+  source.lines().take(20).any(looks_like_jsx_line)
+}
+
+fn looks_like_jsx_line(line: &str) -> bool {
+  let mut search_from = 0;
+  while let Some(rel_idx) = line[search_from..].find('<') {
+    let open_idx = search_from + rel_idx;
+    let after = &line[open_idx + 1..];
+    search_from = open_idx + 1;
+
+    let is_open_tag = after
+      .chars()
+      .next()
+      .map(|c| c.is_ascii_alphabetic() || c == '_')
+      .unwrap_or(false);
+    if !is_open_tag {
+      continue;
+    }
+
+    let name_len = after
+      .find(|c: char| !(c.is_ascii_alphanumeric() || c == '_' || c == '.'))
+      .unwrap_or(after.len());
+    let name = &after[..name_len];
+    let tail = &after[name_len..];
+    let closing_tag = format!("</{name}>");
+
+    if tail.contains("/>") || tail.contains(closing_tag.as_str()) {
+      return true;
+    }
+  }
+
+  false
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn detect_media_type_skips_shebang() {
+    let source = "#!/usr/bin/env -S deno run\nconsole.log(1);\n";
+    assert_eq!(detect_media_type(source), MediaType::Unknown);
+  }
+
+  #[test]
+  fn detect_media_type_ts_check_is_javascript() {
+    let source = "// @ts-check\nconst x = 1;\n";
+    assert_eq!(detect_media_type(source), MediaType::JavaScript);
+  }
+
+  #[test]
+  fn detect_media_type_jsx_sniff() {
+    let source = "const el = <div className=\"a\">hi</div>;\n";
+    assert_eq!(detect_media_type(source), MediaType::Tsx);
+  }
+
+  #[test]
+  fn detect_media_type_does_not_misfire_on_html_strings() {
+    let source = "const html = \"</div>\";\nconsole.log(html);\n";
+    assert_eq!(detect_media_type(source), MediaType::Unknown);
+  }
+
+  #[test]
+  fn detect_media_type_does_not_misfire_on_regex_literal() {
+    // Contains both a literal `<` and a `/>`-shaped regex, but never as a
+    // matching open/close pair on the same line.
+    let source = concat!(
+      "const isLess = 1 < 2;\n",
+      "const closingTag = /\\/>/;\n",
+      "console.log(isLess, closingTag);\n",
+    );
+    assert_eq!(detect_media_type(source), MediaType::Unknown);
+  }
+
+  #[test]
+  fn resolve_media_type_uses_ext_override() {
+    assert_eq!(
+      resolve_media_type(&Some("tsx".to_string()), "const x = 1;"),
+      MediaType::Tsx
+    );
+    assert_eq!(
+      resolve_media_type(&Some("js".to_string()), "const x = 1;"),
+      MediaType::JavaScript
+    );
+  }
+
+  #[test]
+  fn resolve_media_type_falls_back_on_unrecognized_ext() {
+    let source = "// @ts-check\nconst x = 1;";
+    assert_eq!(
+      resolve_media_type(&Some("cjs".to_string()), source),
+      MediaType::JavaScript
+    );
+  }
+}
@@ -0,0 +1,74 @@
+// Copyright 2018-2023 the Deno authors. All rights reserved. MIT license.
+
+use deno_ast::ModuleSpecifier;
+use deno_core::error::AnyError;
+use deno_runtime::permissions::PermissionsContainer;
+use deno_runtime::worker::MainWorker;
+
+use crate::proc_state::ProcState;
+
+/// Builds a [`CliMainWorker`] for a resolved main module, carrying whatever
+/// `ProcState` it was created from along for the ride.
+pub struct CliMainWorkerFactory {
+  ps: ProcState,
+}
+
+impl CliMainWorkerFactory {
+  pub(crate) fn new(ps: ProcState) -> Self {
+    Self { ps }
+  }
+
+  pub async fn create_main_worker(
+    &self,
+    main_module: ModuleSpecifier,
+    permissions: PermissionsContainer,
+  ) -> Result<CliMainWorker, AnyError> {
+    let worker =
+      MainWorker::bootstrap_from_options(main_module.clone(), permissions);
+    Ok(CliMainWorker { worker, main_module })
+  }
+}
+
+/// Owns the running worker for a single `deno run` / `deno eval` /
+/// `deno run --watch` invocation.
+pub struct CliMainWorker {
+  worker: MainWorker,
+  main_module: ModuleSpecifier,
+}
+
+impl CliMainWorker {
+  /// Evaluates the main module to completion, drives the event loop until it
+  /// quiesces, and returns the exit code requested by runtime code (e.g. via
+  /// `Deno.exit()`), defaulting to `0`.
+  pub async fn run(&mut self) -> Result<i32, AnyError> {
+    self.worker.execute_main_module(&self.main_module).await?;
+    self.worker.run_event_loop(false).await?;
+    Ok(self.worker.exit_code())
+  }
+
+  /// Like [`Self::run`], but used under the file watcher: the process
+  /// itself isn't torn down once the script finishes, so the caller is
+  /// responsible for acting on the returned exit code (e.g. remembering it
+  /// to report once the watcher itself is terminated, rather than always
+  /// reporting `0`).
+  pub async fn run_for_watcher(&mut self) -> Result<i32, AnyError> {
+    self.run().await
+  }
+
+  /// Re-evaluates `changed_modules` as side modules on the *same* worker and
+  /// resumes its event loop, without bootstrapping a new worker - so
+  /// top-level state created by modules that did *not* change (timers, open
+  /// sockets, server handles, ...) survives the update. If `main_module` is
+  /// among `changed_modules`, it too is re-evaluated as a side module rather
+  /// than through `execute_main_module`. Used by the opt-in
+  /// `--watch-hmr` mode instead of recreating the worker from scratch.
+  pub async fn apply_module_update(
+    &mut self,
+    changed_modules: Vec<ModuleSpecifier>,
+  ) -> Result<(), AnyError> {
+    for specifier in changed_modules {
+      self.worker.execute_side_module(&specifier).await?;
+    }
+    self.worker.run_event_loop(false).await
+  }
+}